@@ -1,91 +1,450 @@
 mod utils;
 
 use std::fs::File;
-use std::io::BufWriter;
-use image::{DynamicImage, ExtendedColorType, GenericImageView, ImageBuffer, ImageEncoder, ImageReader, Pixel, Rgba};
+use std::io::{BufWriter, Write};
+use image::{DynamicImage, ExtendedColorType, ImageEncoder, ImageReader};
+use image::codecs::bmp::BmpEncoder;
 use image::codecs::png::PngEncoder;
+use image::codecs::tga::TgaEncoder;
 use itertools::Itertools;
+use png::{BitDepth, ColorType, Encoder as PngStreamEncoder};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
 
 type Coord = (u32, u32);
 
-fn dark_channel(image: &DynamicImage, patch_size: u32) -> Vec<u8> {
-    let mut dc = Vec::with_capacity((image.width() * image.height()) as usize);
-    for (y, x) in itertools::iproduct!(0..image.height(), 0..image.width()) {
-        let mut minimum = 255;
+/// Box-filter radius used by the guided filter in `refine_transmission`. Exposed at module
+/// scope (rather than kept local to that function) so the tiled pipeline can size its tile
+/// overlap against it too.
+const GUIDED_FILTER_RADIUS: u32 = 30;
+
+/// Output container for the dehazed image and transmission map, dispatched through
+/// `image::ImageEncoder` rather than always writing PNG.
+#[derive(Clone, Copy)]
+enum OutputFormat {
+    Png,
+    Tga,
+    Bmp,
+}
+
+impl OutputFormat {
+    fn from_arg(s: &str) -> Self {
+        match s.to_ascii_lowercase().as_str() {
+            "png" => OutputFormat::Png,
+            "tga" => OutputFormat::Tga,
+            "bmp" => OutputFormat::Bmp,
+            other => panic!("Unsupported output format '{other}' (expected png, tga or bmp)"),
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Png => "png",
+            OutputFormat::Tga => "tga",
+            OutputFormat::Bmp => "bmp",
+        }
+    }
+
+    /// Only the PNG encoder in this pipeline supports 16-bit channels; other containers fall
+    /// back to 8-bit.
+    fn supports_16_bit(&self) -> bool {
+        matches!(self, OutputFormat::Png)
+    }
+}
+
+/// Tunable dehazing parameters plus I/O configuration, parsed from CLI args instead of being
+/// baked into `main`.
+struct DehazeSettings {
+    patch_size: u32,
+    omega: f32,
+    t_0: f32,
+    a_proportion: f32,
+    input_path: String,
+    output_path: String,
+    format: OutputFormat,
+    optimize: bool,
+    tiled: bool,
+}
+
+impl DehazeSettings {
+    fn from_args() -> Self {
+        let mut patch_size = 5u32;
+        let mut omega = 0.95f32;
+        let mut t_0 = 0.1f32;
+        let mut a_proportion = 0.002f32;
+        let mut input_path = "image.jpg".to_string();
+        let mut output_path = None;
+        let mut format = OutputFormat::Png;
+        let mut optimize = false;
+        let mut tiled = false;
+
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--input" => input_path = args.next().expect("--input requires a path"),
+                "--output" => output_path = Some(args.next().expect("--output requires a path")),
+                "--patch-size" => {
+                    patch_size = args.next().expect("--patch-size requires a value").parse().expect("--patch-size must be an integer");
+                }
+                "--omega" => {
+                    omega = args.next().expect("--omega requires a value").parse().expect("--omega must be a float");
+                }
+                "--t0" => {
+                    t_0 = args.next().expect("--t0 requires a value").parse().expect("--t0 must be a float");
+                }
+                "--a-proportion" => {
+                    a_proportion = args.next().expect("--a-proportion requires a value").parse().expect("--a-proportion must be a float");
+                }
+                "--format" => format = OutputFormat::from_arg(&args.next().expect("--format requires a value")),
+                "--optimize" => optimize = true,
+                "--tiled" => tiled = true,
+                other => panic!("Unrecognised argument: {other}"),
+            }
+        }
+
+        let output_path = output_path.unwrap_or_else(|| format!("output.{}", format.extension()));
+
+        DehazeSettings { patch_size, omega, t_0, a_proportion, input_path, output_path, format, optimize, tiled }
+    }
+
+    fn t_map_path(&self) -> String {
+        format!("transmission_map.{}", self.format.extension())
+    }
+}
+
+/// Tries encoding the PNG at a handful of compression/filter combinations and returns the
+/// smallest result. The candidate set includes the encoder's own defaults, so this is never
+/// larger than a plain `PngEncoder::new` write.
+fn optimize_png(buf: &[u8], width: u32, height: u32, color_type: ExtendedColorType) -> Vec<u8> {
+    use image::codecs::png::{CompressionType, FilterType};
+
+    const COMPRESSIONS: [CompressionType; 3] = [CompressionType::Fast, CompressionType::Default, CompressionType::Best];
+    const FILTERS: [FilterType; 6] = [
+        FilterType::NoFilter,
+        FilterType::Sub,
+        FilterType::Up,
+        FilterType::Avg,
+        FilterType::Paeth,
+        FilterType::Adaptive,
+    ];
+
+    let mut best: Option<Vec<u8>> = None;
+    for &compression in &COMPRESSIONS {
+        for &filter in &FILTERS {
+            let mut candidate = Vec::new();
+            let encoder = PngEncoder::new_with_quality(&mut candidate, compression, filter);
+            if encoder.write_image(buf, width, height, color_type).is_err() {
+                continue;
+            }
+            if best.as_ref().is_none_or(|b| candidate.len() < b.len()) {
+                best = Some(candidate);
+            }
+        }
+    }
+
+    best.expect("at least one PNG compression/filter combination must succeed")
+}
+
+/// Writes `buf` (interleaved samples matching `color_type`) to `path`, dispatching to the
+/// encoder for `format` through the shared `image::ImageEncoder` trait. For PNG with
+/// `optimize` set, the smallest of several compression/filter combinations is kept.
+fn write_image_file(path: &str, format: OutputFormat, optimize: bool, buf: &[u8], width: u32, height: u32, color_type: ExtendedColorType) {
+    let file = File::create(path).expect("File create failed");
+    let mut buf_writer = BufWriter::new(file);
+
+    match format {
+        OutputFormat::Png if optimize => {
+            let optimized = optimize_png(buf, width, height, color_type);
+            buf_writer.write_all(&optimized).expect("File write failed");
+        }
+        OutputFormat::Png => PngEncoder::new(&mut buf_writer).write_image(buf, width, height, color_type).unwrap(),
+        OutputFormat::Tga => TgaEncoder::new(&mut buf_writer).write_image(buf, width, height, color_type).unwrap(),
+        OutputFormat::Bmp => BmpEncoder::new(&mut buf_writer).write_image(buf, width, height, color_type).unwrap(),
+    }
+}
+
+fn floatify8(u: u8) -> f32 {
+    u as f32 / 255.0
+}
+
+fn floatify16(u: u16) -> f32 {
+    u as f32 / 65535.0
+}
+
+fn defloatify8(f: f32) -> u8 {
+    (f.clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+fn defloatify16(f: f32) -> u16 {
+    (f.clamp(0.0, 1.0) * 65535.0).round() as u16
+}
+
+// Decoded pixel samples in full precision, independent of the source's on-disk pixel format.
+// Only the RGB channels ever feed the dehazing maths; alpha (if present) is kept aside and
+// carried through untouched into the output.
+struct ImageSamples {
+    width: u32,
+    height: u32,
+    /// Interleaved R, G, B, normalized to [0, 1] regardless of the source bit depth.
+    rgb: Vec<f32>,
+    /// Per-pixel alpha, normalized to [0, 1], present only if the source had an alpha channel.
+    alpha: Option<Vec<f32>>,
+    /// Whether the source used 16-bit channels, so the output can match its precision.
+    high_bit_depth: bool,
+}
+
+impl ImageSamples {
+    fn decode(image: &DynamicImage) -> Self {
+        match image {
+            DynamicImage::ImageLuma8(buf) => {
+                let (width, height) = buf.dimensions();
+                let rgb = buf.pixels().flat_map(|p| { let v = floatify8(p.0[0]); [v, v, v] }).collect();
+                ImageSamples { width, height, rgb, alpha: None, high_bit_depth: false }
+            }
+            DynamicImage::ImageLumaA8(buf) => {
+                let (width, height) = buf.dimensions();
+                let mut rgb = Vec::with_capacity((width * height * 3) as usize);
+                let mut alpha = Vec::with_capacity((width * height) as usize);
+                for p in buf.pixels() {
+                    let v = floatify8(p.0[0]);
+                    rgb.extend_from_slice(&[v, v, v]);
+                    alpha.push(floatify8(p.0[1]));
+                }
+                ImageSamples { width, height, rgb, alpha: Some(alpha), high_bit_depth: false }
+            }
+            DynamicImage::ImageRgb8(buf) => {
+                let (width, height) = buf.dimensions();
+                let rgb = buf.pixels().flat_map(|p| p.0.map(floatify8)).collect();
+                ImageSamples { width, height, rgb, alpha: None, high_bit_depth: false }
+            }
+            DynamicImage::ImageRgba8(buf) => {
+                let (width, height) = buf.dimensions();
+                let mut rgb = Vec::with_capacity((width * height * 3) as usize);
+                let mut alpha = Vec::with_capacity((width * height) as usize);
+                for p in buf.pixels() {
+                    rgb.extend_from_slice(&[floatify8(p.0[0]), floatify8(p.0[1]), floatify8(p.0[2])]);
+                    alpha.push(floatify8(p.0[3]));
+                }
+                ImageSamples { width, height, rgb, alpha: Some(alpha), high_bit_depth: false }
+            }
+            DynamicImage::ImageLuma16(buf) => {
+                let (width, height) = buf.dimensions();
+                let rgb = buf.pixels().flat_map(|p| { let v = floatify16(p.0[0]); [v, v, v] }).collect();
+                ImageSamples { width, height, rgb, alpha: None, high_bit_depth: true }
+            }
+            DynamicImage::ImageLumaA16(buf) => {
+                let (width, height) = buf.dimensions();
+                let mut rgb = Vec::with_capacity((width * height * 3) as usize);
+                let mut alpha = Vec::with_capacity((width * height) as usize);
+                for p in buf.pixels() {
+                    let v = floatify16(p.0[0]);
+                    rgb.extend_from_slice(&[v, v, v]);
+                    alpha.push(floatify16(p.0[1]));
+                }
+                ImageSamples { width, height, rgb, alpha: Some(alpha), high_bit_depth: true }
+            }
+            DynamicImage::ImageRgb16(buf) => {
+                let (width, height) = buf.dimensions();
+                let rgb = buf.pixels().flat_map(|p| p.0.map(floatify16)).collect();
+                ImageSamples { width, height, rgb, alpha: None, high_bit_depth: true }
+            }
+            DynamicImage::ImageRgba16(buf) => {
+                let (width, height) = buf.dimensions();
+                let mut rgb = Vec::with_capacity((width * height * 3) as usize);
+                let mut alpha = Vec::with_capacity((width * height) as usize);
+                for p in buf.pixels() {
+                    rgb.extend_from_slice(&[floatify16(p.0[0]), floatify16(p.0[1]), floatify16(p.0[2])]);
+                    alpha.push(floatify16(p.0[3]));
+                }
+                ImageSamples { width, height, rgb, alpha: Some(alpha), high_bit_depth: true }
+            }
+            // Rgb32F/Rgba32F and anything future: fall back to an 8-bit RGB conversion, dropping alpha.
+            other => {
+                let buf = other.to_rgb8();
+                let (width, height) = buf.dimensions();
+                let rgb = buf.pixels().flat_map(|p| p.0.map(floatify8)).collect();
+                ImageSamples { width, height, rgb, alpha: None, high_bit_depth: false }
+            }
+        }
+    }
+
+    fn rgb_at(&self, x: u32, y: u32) -> (f32, f32, f32) {
+        let i = ((y * self.width + x) * 3) as usize;
+        (self.rgb[i], self.rgb[i + 1], self.rgb[i + 2])
+    }
+}
+
+fn dark_channel_row(image: &ImageSamples, patch_size: u32, y: u32, row: &mut [f32]) {
+    for (x, minimum) in row.iter_mut().enumerate() {
+        let x = x as u32;
+        *minimum = 1.0;
 
         for yp in 0..patch_size {
-            if yp > y + (patch_size / 2) || ((y + (patch_size / 2)) - yp) >= image.height() {
+            if yp > y + (patch_size / 2) || ((y + (patch_size / 2)) - yp) >= image.height {
                 continue;
             }
             for xp in 0..patch_size {
-                if xp > (x + patch_size / 2) || ((x + (patch_size / 2)) - xp) >= image.width() {
+                if xp > (x + patch_size / 2) || ((x + (patch_size / 2)) - xp) >= image.width {
                     continue;
                 }
 
-                let p = image.get_pixel((x + (patch_size / 2)) - xp, (y + (patch_size / 2)) - yp);
-                p.channels().iter().for_each(|c| minimum = minimum.min(*c));
+                let (r, g, b) = image.rgb_at((x + (patch_size / 2)) - xp, (y + (patch_size / 2)) - yp);
+                *minimum = (*minimum).min(r).min(g).min(b);
             }
         }
+    }
+}
 
-        dc.push(minimum);
+fn dark_channel(image: &ImageSamples, patch_size: u32) -> Vec<f32> {
+    let width = image.width;
+    let height = image.height;
+    let mut dc = vec![0f32; (width * height) as usize];
+
+    #[cfg(feature = "parallel")]
+    {
+        dc.par_chunks_mut(width as usize).enumerate().for_each(|(y, row)| {
+            dark_channel_row(image, patch_size, y as u32, row);
+        });
+    }
+    #[cfg(not(feature = "parallel"))]
+    {
+        for y in 0..height {
+            let row = &mut dc[(y * width) as usize..((y + 1) * width) as usize];
+            dark_channel_row(image, patch_size, y, row);
+        }
     }
 
     dc
 }
 
-fn transmission_map(mut dark_map: Vec<u8>, omega: f32) -> Vec<u8> {
-    dark_map.iter_mut().for_each(|d| *d = 255 - (*d as f32 * omega) as u8);
+fn transmission_map(mut dark_map: Vec<f32>, omega: f32) -> Vec<f32> {
+    dark_map.iter_mut().for_each(|d| *d = (1.0 - omega * *d).clamp(0.0, 1.0));
     dark_map
 }
 
-fn get_atmospheric(dark_map: &[u8], image: &DynamicImage, a_proportion: f32) -> (u8, u8, u8) {
-    let brightest = dark_map.iter().enumerate().sorted_by(|(_, d), (_, d2)| Ord::cmp(&d2, &d)).take((dark_map.len() as f32 * (a_proportion)) as usize).map(|(i, d)| i).collect_vec();
+fn box_filter_mean(data: &[f32], width: u32, height: u32, radius: u32) -> Vec<f32> {
+    let w = width as usize;
+    let h = height as usize;
+
+    // Integral image with a leading row/column of zeros so window sums are a single lookup.
+    let mut integral = vec![0f64; (w + 1) * (h + 1)];
+    for y in 0..h {
+        let mut row_sum = 0f64;
+        for x in 0..w {
+            row_sum += data[y * w + x] as f64;
+            integral[(y + 1) * (w + 1) + (x + 1)] = integral[y * (w + 1) + (x + 1)] + row_sum;
+        }
+    }
+
+    let sum_region = |x0: usize, y0: usize, x1: usize, y1: usize| -> f64 {
+        integral[y1 * (w + 1) + x1] - integral[y0 * (w + 1) + x1] - integral[y1 * (w + 1) + x0]
+            + integral[y0 * (w + 1) + x0]
+    };
+
+    let mut means = Vec::with_capacity(w * h);
+    for y in 0..h {
+        let y0 = y.saturating_sub(radius as usize);
+        let y1 = (y + radius as usize + 1).min(h);
+        for x in 0..w {
+            let x0 = x.saturating_sub(radius as usize);
+            let x1 = (x + radius as usize + 1).min(w);
+            let count = ((y1 - y0) * (x1 - x0)) as f64;
+            means.push((sum_region(x0, y0, x1, y1) / count) as f32);
+        }
+    }
+
+    means
+}
+
+// Guided filter (He et al.) refinement of the raw transmission map, using the grayscale
+// image as the guidance signal. Box filters are O(N) via the integral images above, so the
+// whole pass stays linear in pixel count despite the window radius.
+//
+// Near the image border, `box_filter_mean`'s windows are normalized by their true
+// (possibly truncated) pixel count rather than padding with an edge-replicated window; this
+// is a deliberate deviation from He et al.'s formulation, not an oversight. It's also why
+// tiled callers need at least `2 * GUIDED_FILTER_RADIUS` pixels of real neighbouring image
+// data padded around a tile (the box filters run in two stages): otherwise every tile edge
+// is treated like an image border.
+fn refine_transmission(image: &ImageSamples, t_map: &[f32]) -> Vec<f32> {
+    const EPS: f32 = 1e-4;
+
+    let width = image.width;
+    let height = image.height;
+
+    let i_vals: Vec<f32> = image
+        .rgb
+        .chunks_exact(3)
+        .map(|p| 0.299 * p[0] + 0.587 * p[1] + 0.114 * p[2])
+        .collect();
+    let it_vals: Vec<f32> = i_vals.iter().zip(t_map).map(|(i, t)| i * t).collect();
+    let ii_vals: Vec<f32> = i_vals.iter().map(|i| i * i).collect();
+
+    let mean_i = box_filter_mean(&i_vals, width, height, GUIDED_FILTER_RADIUS);
+    let mean_t = box_filter_mean(t_map, width, height, GUIDED_FILTER_RADIUS);
+    let mean_it = box_filter_mean(&it_vals, width, height, GUIDED_FILTER_RADIUS);
+    let mean_ii = box_filter_mean(&ii_vals, width, height, GUIDED_FILTER_RADIUS);
+
+    let mut a = Vec::with_capacity(i_vals.len());
+    let mut b = Vec::with_capacity(i_vals.len());
+    for idx in 0..i_vals.len() {
+        let var_i = mean_ii[idx] - mean_i[idx] * mean_i[idx];
+        let cov_it = mean_it[idx] - mean_i[idx] * mean_t[idx];
+        let a_i = cov_it / (var_i + EPS);
+        let b_i = mean_t[idx] - a_i * mean_i[idx];
+        a.push(a_i);
+        b.push(b_i);
+    }
 
-    let mut best_i = 0;
-    let mut best_px = (255, 255, 255);
+    let mean_a = box_filter_mean(&a, width, height, GUIDED_FILTER_RADIUS);
+    let mean_b = box_filter_mean(&b, width, height, GUIDED_FILTER_RADIUS);
+
+    i_vals
+        .iter()
+        .zip(mean_a.iter().zip(&mean_b))
+        .map(|(i, (a, b))| (a * i + b).clamp(0.0, 1.0))
+        .collect()
+}
+
+fn get_atmospheric(dark_map: &[f32], image: &ImageSamples, a_proportion: f32) -> (f32, f32, f32) {
+    let brightest = dark_map
+        .iter()
+        .enumerate()
+        .sorted_by(|(_, d), (_, d2)| d2.partial_cmp(d).unwrap())
+        .take((dark_map.len() as f32 * a_proportion) as usize)
+        .map(|(i, _)| i)
+        .collect_vec();
+
+    let mut best_i = 0.0;
+    let mut best_px = (1.0, 1.0, 1.0);
     for i in brightest {
-        let x = i as u32 % image.width();
-        let y = i as u32 / image.width();
-        let px = image.get_pixel(x, y).0;
-        let intensity = px[0].max(px[1]).max(px[2]);
+        let x = i as u32 % image.width;
+        let y = i as u32 / image.width;
+        let (r, g, b) = image.rgb_at(x, y);
+        let intensity = r.max(g).max(b);
         if intensity > best_i {
             best_i = intensity;
-            best_px = (px[0], px[1], px[2])
+            best_px = (r, g, b)
         }
     }
 
     best_px
 }
 
-fn floatify(u: u8) -> f32 {
-    u as f32 / 255.0
-}
-
-fn defloatify(f: f32) -> u8 {
-    (f.clamp(0.0, 1.0) * 255.0).round() as u8
-}
-
-fn reconstruct(image: &DynamicImage, atmospheric: &(u8, u8, u8), transmission_map: &[u8], t_0: f32) -> Vec<u8> {
-    let atmospheric = (
-        floatify(atmospheric.0),
-        floatify(atmospheric.1),
-        floatify(atmospheric.2),
-    );
-    let mut output = Vec::with_capacity(transmission_map.len() * 3);
-    for (x, y, pixel) in image.pixels() {
-        let pixel = (
-            floatify(pixel.0[0]),
-            floatify(pixel.0[1]),
-            floatify(pixel.0[2]),
-        );
+fn reconstruct_row(
+    image: &ImageSamples,
+    atmospheric: &(f32, f32, f32),
+    transmission_map: &[f32],
+    t_0: f32,
+    y: u32,
+    row: &mut [f32],
+) {
+    for x in 0..image.width {
+        let (r, g, b) = image.rgb_at(x, y);
 
-        let numerator = (
-            pixel.0 - atmospheric.0,
-            pixel.1 - atmospheric.1,
-            pixel.2 - atmospheric.2,
-        );
+        let numerator = (r - atmospheric.0, g - atmospheric.1, b - atmospheric.2);
 
-        let t = floatify(transmission_map[(y * image.width() + x) as usize]).max(t_0);
+        let t = transmission_map[(y * image.width + x) as usize].max(t_0);
 
         let j = (
             (numerator.0 / t) + atmospheric.0,
@@ -93,81 +452,336 @@ fn reconstruct(image: &DynamicImage, atmospheric: &(u8, u8, u8), transmission_ma
             (numerator.2 / t) + atmospheric.2,
         );
 
-        output.push(defloatify(j.0));
-        output.push(defloatify(j.1));
-        output.push(defloatify(j.2));
+        row[(x * 3) as usize] = j.0.clamp(0.0, 1.0);
+        row[(x * 3 + 1) as usize] = j.1.clamp(0.0, 1.0);
+        row[(x * 3 + 2) as usize] = j.2.clamp(0.0, 1.0);
+    }
+}
+
+fn reconstruct(image: &ImageSamples, atmospheric: &(f32, f32, f32), transmission_map: &[f32], t_0: f32) -> Vec<f32> {
+    let width = image.width;
+    let mut output = vec![0f32; (image.width * image.height * 3) as usize];
+
+    #[cfg(feature = "parallel")]
+    {
+        output.par_chunks_mut((width * 3) as usize).enumerate().for_each(|(y, row)| {
+            reconstruct_row(image, atmospheric, transmission_map, t_0, y as u32, row);
+        });
     }
+    #[cfg(not(feature = "parallel"))]
+    {
+        for y in 0..image.height {
+            let row = &mut output[(y * width * 3) as usize..((y + 1) * width * 3) as usize];
+            reconstruct_row(image, atmospheric, transmission_map, t_0, y, row);
+        }
+    }
+
     output
 }
 
+/// Copies out the `[x0, x1) x [y0, y1)` sub-rectangle of `image` as its own owned
+/// `ImageSamples`, so the existing per-image passes can run against just a tile.
+fn tile_view(image: &ImageSamples, x0: u32, y0: u32, x1: u32, y1: u32) -> ImageSamples {
+    let width = x1 - x0;
+    let height = y1 - y0;
+    let mut rgb = Vec::with_capacity((width * height * 3) as usize);
+    let mut alpha = image.alpha.as_ref().map(|_| Vec::with_capacity((width * height) as usize));
+
+    for y in y0..y1 {
+        for x in x0..x1 {
+            let (r, g, b) = image.rgb_at(x, y);
+            rgb.extend_from_slice(&[r, g, b]);
+            if let (Some(dst), Some(src)) = (&mut alpha, &image.alpha) {
+                dst.push(src[(y * image.width + x) as usize]);
+            }
+        }
+    }
+
+    ImageSamples { width, height, rgb, alpha, high_bit_depth: image.high_bit_depth }
+}
+
+/// A cheap, coarse stand-in for `image`, sampled every `stride` pixels. Used only to estimate
+/// atmospheric light once up front so the expensive tiled passes don't each need it.
+fn downsampled(image: &ImageSamples, stride: u32) -> ImageSamples {
+    let width = image.width.div_ceil(stride);
+    let height = image.height.div_ceil(stride);
+    let mut rgb = Vec::with_capacity((width * height * 3) as usize);
+    let mut alpha = image.alpha.as_ref().map(|_| Vec::with_capacity((width * height) as usize));
+
+    for y in 0..height {
+        let src_y = (y * stride).min(image.height - 1);
+        for x in 0..width {
+            let src_x = (x * stride).min(image.width - 1);
+            let (r, g, b) = image.rgb_at(src_x, src_y);
+            rgb.extend_from_slice(&[r, g, b]);
+            if let (Some(dst), Some(src)) = (&mut alpha, &image.alpha) {
+                dst.push(src[(src_y * image.width + src_x) as usize]);
+            }
+        }
+    }
+
+    ImageSamples { width, height, rgb, alpha, high_bit_depth: image.high_bit_depth }
+}
+
+/// One overlapping horizontal band of `image`, dehazed against the whole-image `atmospheric`
+/// estimate, plus the padded y-offset the band starts at (needed to find a given output row
+/// within it). The band covers the full width, padded by `margin` rows above and below
+/// `tile_y0..tile_y1`, so both the dark-channel patch minima and the guided filter's
+/// box-filter windows (see `GUIDED_FILTER_RADIUS`) see their true neighbourhood right up to
+/// the band's top and bottom edges.
+fn dehaze_band(image: &ImageSamples, atmospheric: &(f32, f32, f32), settings: &DehazeSettings, margin: u32, tile_y0: u32, tile_y1: u32) -> (ImageSamples, Vec<f32>, Vec<f32>, u32) {
+    let padded_y0 = tile_y0.saturating_sub(margin);
+    let padded_y1 = (tile_y1 + margin).min(image.height);
+
+    let band = tile_view(image, 0, padded_y0, image.width, padded_y1);
+    let band_dark = dark_channel(&band, settings.patch_size);
+    let band_t_map = transmission_map(band_dark, settings.omega);
+    let band_t_map = refine_transmission(&band, &band_t_map);
+    let band_reconstruction = reconstruct(&band, atmospheric, &band_t_map, settings.t_0);
+
+    (band, band_t_map, band_reconstruction, padded_y0)
+}
+
+/// Tiled dehazing for very large images, processed as overlapping horizontal bands (full
+/// width, `BAND_HEIGHT` rows tall) rather than holding the whole image in every pass at once.
+/// Bands overlap by `patch_size / 2 + 2 * GUIDED_FILTER_RADIUS` on top and bottom: the guided
+/// filter box-filters twice (once for `a`/`b`, again for their means), so an interior pixel's
+/// refined value depends on samples up to `2 * GUIDED_FILTER_RADIUS` away, and each of those
+/// must itself have an untruncated dark-channel patch (radius `patch_size / 2`) behind it, or
+/// the box filters still end up averaging in truncated edge values. Summing the reaches is
+/// what keeps the band's interior truly border-free. Only each band's
+/// non-overlapping interior rows are kept. Atmospheric light is estimated once, from a cheap
+/// downsampled pass over the whole image, and reused for every band.
+///
+/// For PNG output, each band's interior rows are quantized and streamed straight to the
+/// output files as they're ready (`dehaze_tiled_streamed`), so peak memory is bounded by a
+/// handful of bands rather than the whole image. TGA and BMP have no streaming encoder in
+/// this crate, so their bands are instead accumulated into full-size buffers and written
+/// once at the end (`dehaze_tiled_buffered`), the same as the non-tiled path — `--tiled` does
+/// *not* bound peak memory for those two formats, only for PNG, so a warning is printed when
+/// falling back to the buffered path. Either way, the initial decode in `main` still reads
+/// the whole input image up front: `image` has no tiled or progressive decoder to read bands
+/// from directly.
+fn dehaze_tiled(image: &ImageSamples, settings: &DehazeSettings) {
+    const BAND_HEIGHT: u32 = 512;
+    const DOWNSAMPLE_STRIDE: u32 = 8;
+
+    let preview = downsampled(image, DOWNSAMPLE_STRIDE);
+    let preview_dark = dark_channel(&preview, (settings.patch_size / DOWNSAMPLE_STRIDE).max(1));
+    let atmospheric = get_atmospheric(&preview_dark, &preview, settings.a_proportion);
+    let margin = settings.patch_size / 2 + 2 * GUIDED_FILTER_RADIUS;
+
+    if matches!(settings.format, OutputFormat::Png) {
+        dehaze_tiled_streamed(image, settings, &atmospheric, margin, BAND_HEIGHT);
+    } else {
+        eprintln!(
+            "Warning: --tiled only bounds peak memory for --format png; {} output still \
+             buffers the full image before writing.",
+            settings.format.extension()
+        );
+        dehaze_tiled_buffered(image, settings, &atmospheric, margin, BAND_HEIGHT);
+    }
+}
+
+/// Bounded-memory tiled path for PNG output: each band is written straight to the
+/// transmission-map and reconstruction PNGs via `png::Writer::stream_writer` as soon as it's
+/// computed, so the full-image `t_map`/reconstruction buffers that the non-streaming formats
+/// need never get allocated here. Note `--optimize` has no effect in this path: comparing
+/// compression/filter combinations needs the whole encoded output held at once, which would
+/// defeat the point of streaming, so bands are always written at the encoder's defaults.
+fn dehaze_tiled_streamed(image: &ImageSamples, settings: &DehazeSettings, atmospheric: &(f32, f32, f32), margin: u32, band_height: u32) {
+    let has_alpha = image.alpha.is_some();
+    let high_bit_depth = image.high_bit_depth;
+
+    let mut t_map_encoder = PngStreamEncoder::new(BufWriter::new(File::create(settings.t_map_path()).expect("File create failed")), image.width, image.height);
+    t_map_encoder.set_color(ColorType::Rgb);
+    t_map_encoder.set_depth(BitDepth::Eight);
+    let mut t_map_writer = t_map_encoder.write_header().expect("PNG header write failed");
+    let mut t_map_stream = t_map_writer.stream_writer().expect("PNG stream writer failed");
+
+    let mut output_encoder = PngStreamEncoder::new(BufWriter::new(File::create(&settings.output_path).expect("File create failed")), image.width, image.height);
+    output_encoder.set_color(if has_alpha { ColorType::Rgba } else { ColorType::Rgb });
+    output_encoder.set_depth(if high_bit_depth { BitDepth::Sixteen } else { BitDepth::Eight });
+    let mut output_writer = output_encoder.write_header().expect("PNG header write failed");
+    let mut output_stream = output_writer.stream_writer().expect("PNG stream writer failed");
+
+    let mut tile_y0 = 0;
+    while tile_y0 < image.height {
+        let tile_y1 = (tile_y0 + band_height).min(image.height);
+        let (band, band_t_map, band_reconstruction, padded_y0) = dehaze_band(image, atmospheric, settings, margin, tile_y0, tile_y1);
+
+        let mut t_map_bytes = Vec::with_capacity(((tile_y1 - tile_y0) * image.width * 3) as usize);
+        let mut output_bytes = Vec::new();
+
+        for y in tile_y0..tile_y1 {
+            let band_y = y - padded_y0;
+            for x in 0..image.width {
+                let idx = (band_y * band.width + x) as usize;
+
+                let t = defloatify8(band_t_map[idx]);
+                t_map_bytes.extend_from_slice(&[t, t, t]);
+
+                if high_bit_depth {
+                    for c in 0..3 {
+                        output_bytes.extend_from_slice(&defloatify16(band_reconstruction[idx * 3 + c]).to_be_bytes());
+                    }
+                    if let Some(alpha) = &band.alpha {
+                        output_bytes.extend_from_slice(&defloatify16(alpha[idx]).to_be_bytes());
+                    }
+                } else {
+                    for c in 0..3 {
+                        output_bytes.push(defloatify8(band_reconstruction[idx * 3 + c]));
+                    }
+                    if let Some(alpha) = &band.alpha {
+                        output_bytes.push(defloatify8(alpha[idx]));
+                    }
+                }
+            }
+        }
+
+        t_map_stream.write_all(&t_map_bytes).expect("PNG data write failed");
+        output_stream.write_all(&output_bytes).expect("PNG data write failed");
+
+        tile_y0 = tile_y1;
+    }
+
+    t_map_stream.finish().expect("PNG finalize failed");
+    output_stream.finish().expect("PNG finalize failed");
+}
+
+/// Fallback tiled path for TGA/BMP output, which have no streaming encoder in this crate:
+/// bands are accumulated into full-size `t_map`/reconstruction buffers, same shape as the
+/// non-tiled pipeline, and handed to `output_t_map`/`output_reconstruct` once every band has
+/// been computed. This holds as much memory as the non-tiled path for the final buffers, but
+/// still avoids ever running the dark-channel/guided-filter/reconstruct passes over more than
+/// one band at a time.
+fn dehaze_tiled_buffered(image: &ImageSamples, settings: &DehazeSettings, atmospheric: &(f32, f32, f32), margin: u32, band_height: u32) {
+    let mut t_map = vec![0f32; (image.width * image.height) as usize];
+    let mut reconstruction = vec![0f32; (image.width * image.height * 3) as usize];
+
+    let mut tile_y0 = 0;
+    while tile_y0 < image.height {
+        let tile_y1 = (tile_y0 + band_height).min(image.height);
+        let (band, band_t_map, band_reconstruction, padded_y0) = dehaze_band(image, atmospheric, settings, margin, tile_y0, tile_y1);
+
+        for y in tile_y0..tile_y1 {
+            let band_y = y - padded_y0;
+            for x in 0..image.width {
+                let src_idx = (band_y * band.width + x) as usize;
+                let dst_idx = (y * image.width + x) as usize;
+
+                t_map[dst_idx] = band_t_map[src_idx];
+                reconstruction[dst_idx * 3] = band_reconstruction[src_idx * 3];
+                reconstruction[dst_idx * 3 + 1] = band_reconstruction[src_idx * 3 + 1];
+                reconstruction[dst_idx * 3 + 2] = band_reconstruction[src_idx * 3 + 2];
+            }
+        }
+
+        tile_y0 = tile_y1;
+    }
+
+    output_t_map(&t_map, image, settings);
+    output_reconstruct(&reconstruction, image, settings);
+}
+
 fn main() {
-    const PATCH_SIZE: u32 = 5;
-    const OMEGA: f32 = 0.95;
-    const T_0: f32 = 0.1;
-    const A_PROPORTION: f32 = 0.002;
+    let settings = DehazeSettings::from_args();
 
     print!("Loading image... ");
     time!(
-        let image = ImageReader::open("image.jpg").unwrap().decode().unwrap();
+        let image = ImageReader::open(&settings.input_path).unwrap().decode().unwrap();
+        let samples = ImageSamples::decode(&image);
     );
 
+    if settings.tiled {
+        print!("Dehazing in tiles... ");
+        time!(
+            dehaze_tiled(&samples, &settings);
+        );
+
+        return;
+    }
+
     print!("Calculating dark channel... ");
     time!(
-        let dark_channel = dark_channel(&image, PATCH_SIZE);
+        let dark_channel = dark_channel(&samples, settings.patch_size);
     );
 
     print!("Calculating atmospheric... ");
     time!(
-        let atmospheric = get_atmospheric(&dark_channel, &image, A_PROPORTION);
-        // let atmospheric: (u8, u8, u8) = (213,214,213);
+        let atmospheric = get_atmospheric(&dark_channel, &samples, settings.a_proportion);
     );
 
-    println!("Using atmospheric value: {:?}", atmospheric);
+    println!(
+        "Using atmospheric value: {:?}",
+        (defloatify8(atmospheric.0), defloatify8(atmospheric.1), defloatify8(atmospheric.2))
+    );
 
     print!("Calculating transmission map... ");
     time!(
-        let t_map = transmission_map(dark_channel, OMEGA);
+        let t_map = transmission_map(dark_channel, settings.omega);
+    );
+
+    print!("Refining transmission map... ");
+    time!(
+        let t_map = refine_transmission(&samples, &t_map);
     );
 
     print!("Outputting transmission map image... ");
     time!(
-        output_t_map(&t_map, &image);
+        output_t_map(&t_map, &samples, &settings);
     );
 
     print!("Reconstructing... ");
     time!(
-        let reconstruct = reconstruct(&image, &atmospheric, &t_map, T_0);
+        let reconstruct = reconstruct(&samples, &atmospheric, &t_map, settings.t_0);
     );
 
     print!("Outputting reconstruction... ");
     time!(
-        output_reconstruct(&reconstruct, &image);
+        output_reconstruct(&reconstruct, &samples, &settings);
     );
 }
 
-fn output_t_map(t_map: &[u8], image: &DynamicImage) {
+fn output_t_map(t_map: &[f32], image: &ImageSamples, settings: &DehazeSettings) {
     let mut t_map_output = Vec::with_capacity(t_map.len() * 3);
-    t_map.iter().for_each(|c| {
-        t_map_output.push(*c);
-        t_map_output.push(*c);
-        t_map_output.push(*c);
+    t_map.iter().for_each(|&t| {
+        let v = defloatify8(t);
+        t_map_output.push(v);
+        t_map_output.push(v);
+        t_map_output.push(v);
     });
 
-    let output_path = "transmission_map.png";
-    let file = File::create(output_path).expect("File create failed");
-    let ref mut buf_writer = BufWriter::new(file);
-
-    let encoder = PngEncoder::new(buf_writer);
-    // print!("W: {}, H: {}, WH: {}, WH3: {}, LEN: {}", image.width(), image.height(), image.width() * image.height(), image.width() * image.height() * 3, t_map_output.len());
-    encoder.write_image(&t_map_output, image.width(), image.height(), ExtendedColorType::Rgb8).unwrap();
+    write_image_file(&settings.t_map_path(), settings.format, settings.optimize, &t_map_output, image.width, image.height, ExtendedColorType::Rgb8);
 }
 
-fn output_reconstruct(reconstruct: &[u8], image: &DynamicImage) {
-    let output_path = "output.png";
-    let file = File::create(output_path).expect("File create failed");
-    let ref mut buf_writer = BufWriter::new(file);
+fn output_reconstruct(reconstruct: &[f32], image: &ImageSamples, settings: &DehazeSettings) {
+    let pixel_count = (image.width * image.height) as usize;
+    let has_alpha = image.alpha.is_some();
+    let high_bit_depth = image.high_bit_depth && settings.format.supports_16_bit();
+
+    let mut interleaved = Vec::with_capacity(pixel_count * if has_alpha { 4 } else { 3 });
+    for i in 0..pixel_count {
+        interleaved.push(reconstruct[i * 3]);
+        interleaved.push(reconstruct[i * 3 + 1]);
+        interleaved.push(reconstruct[i * 3 + 2]);
+        if let Some(alpha) = &image.alpha {
+            interleaved.push(alpha[i]);
+        }
+    }
+
+    let color_type = match (high_bit_depth, has_alpha) {
+        (false, false) => ExtendedColorType::Rgb8,
+        (false, true) => ExtendedColorType::Rgba8,
+        (true, false) => ExtendedColorType::Rgb16,
+        (true, true) => ExtendedColorType::Rgba16,
+    };
 
-    let encoder = PngEncoder::new(buf_writer);
-    // print!("W: {}, H: {}, WH: {}, WH3: {}, LEN: {}", image.width(), image.height(), image.width() * image.height(), image.width() * image.height() * 3, t_map_output.len());
-    encoder.write_image(reconstruct, image.width(), image.height(), ExtendedColorType::Rgb8).unwrap();
-}
\ No newline at end of file
+    let buf: Vec<u8> = if high_bit_depth {
+        interleaved.iter().flat_map(|&v| defloatify16(v).to_ne_bytes()).collect()
+    } else {
+        interleaved.iter().map(|&v| defloatify8(v)).collect()
+    };
+
+    write_image_file(&settings.output_path, settings.format, settings.optimize, &buf, image.width, image.height, color_type);
+}